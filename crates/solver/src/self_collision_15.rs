@@ -1,14 +1,16 @@
-use glam::{vec3, Vec3};
+use crate::tangent_space;
+use glam::{vec3, Mat3, Vec2, Vec3, Vec4};
 use rand::Rng;
 
-use crate::hashing_11::Hash;
-
 const GRAVITY: Vec3 = vec3(0.0, -10.0, 0.0);
 pub const TIME_STEP: f32 = 1.0 / 60.0;
 pub const DEFAULT_NUM_SOLVER_SUBSTEPS: usize = 10;
 pub const DEFAULT_BENDING_COMPLIANCE: f32 = 1.0;
 pub const DEFAULT_STRETCH_COMPLIANCE: f32 = 0.0;
 pub const DEFAULT_SHEAR_COMPLIANCE: f32 = 0.0001;
+pub const DEFAULT_SPRING_DAMPING: f32 = 0.01;
+pub const DEFAULT_AIR_DENSITY: f32 = 1.2;
+pub const DEFAULT_TANGENTIAL_DRAG: f32 = 0.05;
 
 const VEL_LIMIT_MULTIPLIER: f32 = 0.2;
 const SPACING: f32 = 0.01;
@@ -18,6 +20,35 @@ const NUM_X: usize = 30;
 const NUM_Y: usize = 200;
 const NUM_CONSTRAINTS_PER_PARTICLE: usize = 6;
 
+// lower bound on compliance used when deriving an implicit spring's stiffness
+// (1 / compliance), since the XPBD defaults allow a compliance of exactly 0
+const MIN_IMPLICIT_COMPLIANCE: f32 = 1e-7;
+const CG_MAX_ITERS: usize = 50;
+const CG_EPSILON: f32 = 1e-7;
+
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub enum SolverMode {
+    /// many small position-based (XPBD) substeps per frame
+    #[default]
+    Xpbd,
+    /// a single implicit (backward) Euler step solved with filtered CG
+    ImplicitCG,
+}
+
+// per-spring data needed by the implicit solver, rebuilt once per step from
+// the current `constraints` list
+struct SpringTerm {
+    ids: (usize, usize),
+    dir: Vec3,
+    len: f32,
+    rest_len: f32,
+    k: f32,
+    // dF = d(spring force)/d(position), the Hookean Jacobian block
+    df: Mat3,
+    // dD = d(damping force)/d(velocity), damping only along the spring axis
+    dd: Mat3,
+}
+
 #[derive(Default, Clone, Copy)]
 enum ConstraintKind {
     STRETCH,
@@ -33,6 +64,71 @@ struct Constraint {
     rest_len: f32,
 }
 
+// axis-aligned bounding box, used by the self-collision BVH broad phase
+#[derive(Clone, Copy)]
+struct Aabb {
+    min: Vec3,
+    max: Vec3,
+}
+
+impl Aabb {
+    fn empty() -> Self {
+        Self {
+            min: Vec3::splat(f32::MAX),
+            max: Vec3::splat(f32::MIN),
+        }
+    }
+
+    fn grow_point(&mut self, p: Vec3) {
+        self.min = self.min.min(p);
+        self.max = self.max.max(p);
+    }
+
+    fn union(a: &Aabb, b: &Aabb) -> Self {
+        Self {
+            min: a.min.min(b.min),
+            max: a.max.max(b.max),
+        }
+    }
+
+    fn expand(&self, margin: f32) -> Self {
+        Self {
+            min: self.min - Vec3::splat(margin),
+            max: self.max + Vec3::splat(margin),
+        }
+    }
+
+    fn overlaps(&self, other: &Aabb) -> bool {
+        self.min.x <= other.max.x
+            && self.max.x >= other.min.x
+            && self.min.y <= other.max.y
+            && self.max.y >= other.min.y
+            && self.min.z <= other.max.z
+            && self.max.z >= other.min.z
+    }
+}
+
+// a node is either a leaf (tri != NONE) holding one triangle of `tri_ids`, or
+// an internal node whose aabb is the union of its two children; children
+// always have a lower index than their parent, which lets `refit` update the
+// whole tree bottom-up with a single forward pass
+struct BvhNode {
+    aabb: Aabb,
+    left: usize,
+    right: usize,
+    tri: usize,
+}
+
+const BVH_NONE: usize = usize::MAX;
+
+// a BVH over the current triangles, used to broad-phase self-collision
+// queries; built once on first use and refit (cheap) every step after that,
+// it is not rebuilt if `tri_ids` changes
+struct Bvh {
+    nodes: Vec<BvhNode>,
+    root: usize,
+}
+
 pub struct Cloth {
     pub num_particles: usize,
     num_substeps: usize,
@@ -45,12 +141,11 @@ pub struct Cloth {
 
     pub pos: Vec<Vec3>,
     prev: Vec<Vec3>,
-    rest_pos: Vec<Vec3>,
     vel: Vec<Vec3>,
     inv_mass: Vec<f32>,
     thickness: f32,
     pub handle_collisions: bool,
-    hash: Hash,
+    bvh: Option<Bvh>,
 
     grab_inv_mass: f32,
     grab_id: Option<usize>,
@@ -61,9 +156,21 @@ pub struct Cloth {
     pub shear_compliance: f32,
     pub bending_compliance: f32,
 
+    pub solver_mode: SolverMode,
+    pub spring_damping: f32,
+
+    pub wind: Vec3,
+    pub wind_gust_amplitude: f32,
+    pub wind_gust_frequency: f32,
+    pub air_density: f32,
+    pub tangential_drag: f32,
+    wind_time: f32,
+
+    pub static_friction: f32,
+    pub dynamic_friction: f32,
+    pub restitution: f32,
+
     grad: Vec3,
-    grad1: Vec3,
-    grad2: Vec3,
 }
 
 impl Cloth {
@@ -97,24 +204,32 @@ impl Cloth {
             max_vel: VEL_LIMIT_MULTIPLIER * THICKNESS / dt,
             pos: vec![Vec3::ZERO; num_particles],
             prev: vec![Vec3::ZERO; num_particles],
-            rest_pos: vec![Vec3::ZERO; num_particles],
             vel: vec![Vec3::ZERO; num_particles],
             inv_mass: vec![0.0; num_particles],
             thickness: THICKNESS,
             handle_collisions: true,
-            hash: Hash::new(SPACING, num_particles),
+            bvh: None,
             grab_id: None,
             grab_inv_mass: 0.0,
             constraints: vec![Constraint::default(); num_particles * NUM_CONSTRAINTS_PER_PARTICLE],
             stretch_compliance: DEFAULT_STRETCH_COMPLIANCE,
             shear_compliance: DEFAULT_SHEAR_COMPLIANCE,
             bending_compliance: DEFAULT_BENDING_COMPLIANCE,
+            solver_mode: SolverMode::default(),
+            spring_damping: DEFAULT_SPRING_DAMPING,
+            wind: Vec3::ZERO,
+            wind_gust_amplitude: 0.0,
+            wind_gust_frequency: 0.0,
+            air_density: DEFAULT_AIR_DENSITY,
+            tangential_drag: DEFAULT_TANGENTIAL_DRAG,
+            wind_time: 0.0,
+            static_friction: 0.0,
+            dynamic_friction: 0.0,
+            restitution: 0.0,
             edge_ids,
             tri_ids,
             num_constraints: 0,
             grad: Vec3::ZERO,
-            grad1: Vec3::ZERO,
-            grad2: Vec3::ZERO,
         };
         cloth.init();
         cloth
@@ -143,7 +258,6 @@ impl Cloth {
             p.z += -JITTER * 2.0 * JITTER * rng.gen::<f32>();
         });
 
-        self.rest_pos.copy_from_slice(&self.pos);
         self.vel.fill(Vec3::ZERO);
     }
 
@@ -199,13 +313,17 @@ impl Cloth {
     }
 
     pub fn simulate(&mut self) {
-        if self.handle_collisions {
-            self.hash.create(&self.pos);
-            let max_dist = self.max_vel * self.dt * self.num_substeps as f32;
-            self.hash.query_all(&self.pos, max_dist);
+        match self.solver_mode {
+            SolverMode::Xpbd => self.simulate_xpbd(),
+            SolverMode::ImplicitCG => self.simulate_implicit(),
         }
+    }
 
+    fn simulate_xpbd(&mut self) {
         for _ in 0..self.num_substeps {
+            self.wind_time += self.dt;
+            self.apply_wind_forces();
+
             // integrate
             for i in 0..self.num_particles {
                 if self.inv_mass[i] == 0.0 {
@@ -221,10 +339,10 @@ impl Cloth {
             }
 
             // solve
-            self.solve_ground_collisions();
+            self.solve_ground_collisions(self.dt);
             self.solve_constraints();
             if self.handle_collisions {
-                self.solve_collisions();
+                self.solve_self_collisions();
             }
 
             // update velocities
@@ -237,6 +355,231 @@ impl Cloth {
         }
     }
 
+    // single large TIME_STEP step via implicit (backward) Euler, following
+    // Blender's mass-spring solver: assemble A = M - dt*dD - dt^2*dF and
+    // solve A*dv = b with a matrix-free, filtered conjugate gradient so
+    // pinned/grabbed particles never move
+    fn simulate_implicit(&mut self) {
+        let dt = TIME_STEP;
+        let n = self.num_particles;
+
+        let free: Vec<bool> = (0..n)
+            .map(|i| self.inv_mass[i] != 0.0 && self.grab_id != Some(i))
+            .collect();
+
+        let springs = self.build_spring_terms();
+
+        let mut force = vec![Vec3::ZERO; n];
+        for i in 0..n {
+            if self.inv_mass[i] != 0.0 {
+                force[i] += GRAVITY / self.inv_mass[i];
+            }
+        }
+        for s in &springs {
+            let f = s.dir * (s.k * (s.len - s.rest_len));
+            force[s.ids.0] -= f;
+            force[s.ids.1] += f;
+        }
+        self.wind_time += dt;
+        let wind_force = self.wind_forces(self.effective_wind());
+        for i in 0..n {
+            force[i] += wind_force[i];
+        }
+
+        let mut rhs = Self::apply_spring_jacobian(n, &springs, &self.vel);
+        for i in 0..n {
+            rhs[i] = dt * (force[i] + dt * rhs[i]);
+        }
+        Self::apply_filter(&mut rhs, &free);
+
+        let dv = self.solve_cg(&springs, dt, &rhs, &free);
+
+        for i in 0..n {
+            if free[i] {
+                self.vel[i] += dv[i];
+                self.prev[i] = self.pos[i];
+                self.pos[i] += self.vel[i] * dt;
+            }
+        }
+
+        // velocities are not capped by `max_vel` in this single TIME_STEP
+        // step; the swept-AABB BVH self-collision below covers each
+        // particle's whole `prev` -> `pos` motion, so it needs no such cap
+        self.solve_ground_collisions(dt);
+        if self.handle_collisions {
+            self.solve_self_collisions();
+        }
+
+        for i in 0..n {
+            if free[i] {
+                self.vel[i] = (self.pos[i] - self.prev[i]) / dt;
+            }
+        }
+    }
+
+    fn build_spring_terms(&self) -> Vec<SpringTerm> {
+        let mut springs = Vec::with_capacity(self.num_constraints);
+        for cons in &self.constraints[..self.num_constraints] {
+            let (id0, id1) = cons.ids;
+            let delta = self.pos[id0] - self.pos[id1];
+            let len = delta.length();
+            if len == 0.0 {
+                continue;
+            }
+            let dir = delta / len;
+            let k = 1.0 / self.get_compliance(cons).max(MIN_IMPLICIT_COMPLIANCE);
+            let outer = Mat3::from_cols(dir * dir.x, dir * dir.y, dir * dir.z);
+            let df = (Mat3::IDENTITY - (Mat3::IDENTITY - outer) * (cons.rest_len / len)) * k;
+            let dd = outer * self.spring_damping;
+            springs.push(SpringTerm {
+                ids: (id0, id1),
+                dir,
+                len,
+                rest_len: cons.rest_len,
+                k,
+                df,
+                dd,
+            });
+        }
+        springs
+    }
+
+    // applies dF (the stiffness-only Jacobian) to `v`, accumulated
+    // symmetrically per spring into the diagonal/off-diagonal blocks
+    fn apply_spring_jacobian(n: usize, springs: &[SpringTerm], v: &[Vec3]) -> Vec<Vec3> {
+        let mut out = vec![Vec3::ZERO; n];
+        for s in springs {
+            let rel = v[s.ids.0] - v[s.ids.1];
+            let contrib = s.df * rel;
+            out[s.ids.0] -= contrib;
+            out[s.ids.1] += contrib;
+        }
+        out
+    }
+
+    // applies A = M - dt*dD - dt^2*dF to `v`
+    fn apply_system(&self, springs: &[SpringTerm], dt: f32, v: &[Vec3]) -> Vec<Vec3> {
+        let mut out = vec![Vec3::ZERO; self.num_particles];
+        for i in 0..self.num_particles {
+            if self.inv_mass[i] != 0.0 {
+                out[i] = v[i] / self.inv_mass[i];
+            }
+        }
+        for s in springs {
+            let combined = s.dd * dt + s.df * (dt * dt);
+            let rel = v[s.ids.0] - v[s.ids.1];
+            let contrib = combined * rel;
+            out[s.ids.0] += contrib;
+            out[s.ids.1] -= contrib;
+        }
+        out
+    }
+
+    fn apply_filter(v: &mut [Vec3], free: &[bool]) {
+        for i in 0..v.len() {
+            if !free[i] {
+                v[i] = Vec3::ZERO;
+            }
+        }
+    }
+
+    // filtered conjugate gradient: the filter zeroes the residual/search
+    // direction for pinned and grabbed particles every iteration, so they
+    // never accumulate a velocity change
+    fn solve_cg(
+        &self,
+        springs: &[SpringTerm],
+        dt: f32,
+        rhs: &[Vec3],
+        free: &[bool],
+    ) -> Vec<Vec3> {
+        let n = self.num_particles;
+        let mut dv = vec![Vec3::ZERO; n];
+        let mut r = rhs.to_vec();
+        let mut p = r.clone();
+        let mut rs_old: f32 = r.iter().map(|x| x.length_squared()).sum();
+
+        if rs_old > CG_EPSILON {
+            for _ in 0..CG_MAX_ITERS {
+                let mut ap = self.apply_system(springs, dt, &p);
+                Self::apply_filter(&mut ap, free);
+
+                let p_dot_ap: f32 = p.iter().zip(&ap).map(|(a, b)| a.dot(*b)).sum();
+                if p_dot_ap.abs() < CG_EPSILON {
+                    break;
+                }
+                let alpha = rs_old / p_dot_ap;
+                for i in 0..n {
+                    dv[i] += p[i] * alpha;
+                    r[i] -= ap[i] * alpha;
+                }
+                Self::apply_filter(&mut r, free);
+
+                let rs_new: f32 = r.iter().map(|x| x.length_squared()).sum();
+                if rs_new < CG_EPSILON {
+                    break;
+                }
+                let beta = rs_new / rs_old;
+                for i in 0..n {
+                    p[i] = r[i] + p[i] * beta;
+                }
+                rs_old = rs_new;
+            }
+        }
+
+        dv
+    }
+
+    // current wind velocity, modulated by an optional sinusoidal gust
+    fn effective_wind(&self) -> Vec3 {
+        if self.wind_gust_amplitude == 0.0 {
+            return self.wind;
+        }
+        let phase = 2.0 * std::f32::consts::PI * self.wind_gust_frequency * self.wind_time;
+        self.wind * (1.0 + self.wind_gust_amplitude * phase.sin())
+    }
+
+    // per-triangle normal (pressure) and tangential drag against the wind,
+    // split evenly onto the triangle's three vertices; returned per-particle
+    // so both the XPBD (velocity impulse) and implicit (force) integrators
+    // can consume it
+    fn wind_forces(&self, wind: Vec3) -> Vec<Vec3> {
+        let mut force = vec![Vec3::ZERO; self.num_particles];
+        for t in 0..self.tri_ids.len() {
+            let [ia, ib, ic] = self.tri_ids[t];
+            let e1 = self.pos[ib] - self.pos[ia];
+            let e2 = self.pos[ic] - self.pos[ia];
+            let cross = e1.cross(e2);
+            let area = 0.5 * cross.length();
+            if area == 0.0 {
+                continue;
+            }
+            let n = cross / (2.0 * area);
+
+            let v_face = (self.vel[ia] + self.vel[ib] + self.vel[ic]) / 3.0;
+            let v_rel = v_face - wind;
+            let v_normal = n.dot(v_rel);
+            let v_tangent = v_rel - n * v_normal;
+
+            let f_normal = n * (-self.air_density * area * v_normal);
+            let f_tangent = v_tangent * (-self.tangential_drag * area);
+            let f = (f_normal + f_tangent) / 3.0;
+
+            force[ia] += f;
+            force[ib] += f;
+            force[ic] += f;
+        }
+        force
+    }
+
+    fn apply_wind_forces(&mut self) {
+        let wind = self.effective_wind();
+        let force = self.wind_forces(wind);
+        for i in 0..self.num_particles {
+            self.vel[i] += force[i] * self.inv_mass[i] * self.dt;
+        }
+    }
+
     fn solve_constraints(&mut self) {
         for cons in &self.constraints {
             let id0 = cons.ids.0;
@@ -262,71 +605,355 @@ impl Cloth {
         }
     }
 
-    fn solve_ground_collisions(&mut self) {
+    // `dt` is the step the caller will use to recompute velocity from
+    // `(pos - prev)` afterwards (the substep dt in XPBD, the full TIME_STEP
+    // in the implicit solver), so the restitution fudge below round-trips
+    // to the right bounce velocity in either mode
+    fn solve_ground_collisions(&mut self, dt: f32) {
         for i in 0..self.num_particles {
             if self.inv_mass[i] == 0.0 {
                 continue;
             }
             if self.pos[i].y < 0.5 * self.thickness {
-                let damping = 1.0;
-                self.grad = self.pos[i] - self.prev[i];
-                self.pos[i] += self.grad * -damping;
+                let v_n_in = self.vel[i].y;
+                let d_n = 0.5 * self.thickness - self.pos[i].y;
+                let tangent = vec3(self.pos[i].x - self.prev[i].x, 0.0, self.pos[i].z - self.prev[i].z);
                 self.pos[i].y = 0.5 * self.thickness;
+
+                // Coulomb friction: stick if the tangential slip is within
+                // the static cone, otherwise clamp it to the dynamic limit
+                let t_len = tangent.length();
+                if t_len > 0.0 {
+                    if t_len < self.static_friction * d_n {
+                        self.pos[i].x = self.prev[i].x;
+                        self.pos[i].z = self.prev[i].z;
+                    } else {
+                        let removed = (self.dynamic_friction * d_n).min(t_len);
+                        let scale = 1.0 - removed / t_len;
+                        self.pos[i].x = self.prev[i].x + tangent.x * scale;
+                        self.pos[i].z = self.prev[i].z + tangent.z * scale;
+                    }
+                }
+
+                // restitution: fudge `prev.y` so the end-of-substep
+                // vel = (pos - prev) * inv_dt reflects the incoming normal
+                // velocity scaled by `restitution`
+                let target_vel_y = if v_n_in < 0.0 { -self.restitution * v_n_in } else { 0.0 };
+                self.prev[i].y = self.pos[i].y - target_vel_y * dt;
             }
         }
     }
 
-    fn solve_collisions(&mut self) {
-        let thickness_sq = self.thickness * self.thickness;
-        for i in 0..self.num_particles {
-            if self.inv_mass[i] == 0.0 {
+    // triangle-aware self-collision: broad-phase via a BVH over swept
+    // triangle AABBs, narrow-phase via continuous vertex-triangle and
+    // edge-edge tests against the `prev` -> `pos` motion of the current step
+    // (an XPBD substep, or the full TIME_STEP in the implicit solver)
+    fn solve_self_collisions(&mut self) {
+        let mut bvh = self.bvh.take().unwrap_or_else(|| self.build_bvh());
+        self.refit_bvh(&mut bvh);
+
+        let mut candidates = Vec::new();
+        for v in 0..self.num_particles {
+            if self.inv_mass[v] == 0.0 {
                 continue;
             }
-            let id0 = i;
-            let first = self.hash.first_adj_id[i];
-            let last = self.hash.first_adj_id[i + 1];
-            for j in first..last {
-                let id1 = self.hash.adj_ids[j];
-                if self.inv_mass[id1] == 0.0 {
-                    continue;
-                }
-                self.grad = self.pos[id1] - self.pos[id0];
-                let dist_sq = self.grad.length_squared();
-                if dist_sq > thickness_sq || dist_sq == 0.0 {
-                    continue;
+            let mut query = Aabb::empty();
+            query.grow_point(self.pos[v]);
+            query.grow_point(self.prev[v]);
+            let query = query.expand(self.thickness);
+
+            candidates.clear();
+            Self::query_bvh(&bvh, bvh.root, &query, &mut candidates);
+            for &tri in &candidates {
+                self.solve_vertex_triangle(v, tri);
+            }
+        }
+
+        for ei in 0..self.edge_ids.len() {
+            let [ia, ib] = self.edge_ids[ei];
+            if self.inv_mass[ia] == 0.0 && self.inv_mass[ib] == 0.0 {
+                continue;
+            }
+            let mut query = Aabb::empty();
+            for &p in &[self.pos[ia], self.pos[ib], self.prev[ia], self.prev[ib]] {
+                query.grow_point(p);
+            }
+            let query = query.expand(self.thickness);
+
+            candidates.clear();
+            Self::query_bvh(&bvh, bvh.root, &query, &mut candidates);
+            for &tri in &candidates {
+                let t = self.tri_ids[tri];
+                for &[ja, jb] in &[[t[0], t[1]], [t[1], t[2]], [t[2], t[0]]] {
+                    if ja == ia || ja == ib || jb == ia || jb == ib {
+                        continue;
+                    }
+                    self.solve_edge_edge((ia, ib), (ja, jb));
                 }
-                let rest_dist_sq = (self.rest_pos[id0] - self.rest_pos[id1]).length();
-                let mut min_dist = self.thickness;
-                if dist_sq > rest_dist_sq {
-                    continue;
+            }
+        }
+
+        self.bvh = Some(bvh);
+    }
+
+    fn build_bvh(&self) -> Bvh {
+        let mut nodes = Vec::with_capacity(2 * self.tri_ids.len());
+        let mut indices: Vec<usize> = (0..self.tri_ids.len()).collect();
+        let root = Self::build_bvh_range(&mut nodes, &mut indices, &self.pos, &self.tri_ids);
+        Bvh { nodes, root }
+    }
+
+    fn tri_centroid(tri: usize, pos: &[Vec3], tri_ids: &[[usize; 3]]) -> Vec3 {
+        let [a, b, c] = tri_ids[tri];
+        (pos[a] + pos[b] + pos[c]) / 3.0
+    }
+
+    fn build_bvh_range(
+        nodes: &mut Vec<BvhNode>,
+        indices: &mut [usize],
+        pos: &[Vec3],
+        tri_ids: &[[usize; 3]],
+    ) -> usize {
+        if indices.len() == 1 {
+            let tri = indices[0];
+            nodes.push(BvhNode {
+                aabb: Aabb::empty(),
+                left: BVH_NONE,
+                right: BVH_NONE,
+                tri,
+            });
+            return nodes.len() - 1;
+        }
+
+        let mut centroid_bounds = Aabb::empty();
+        for &tri in indices.iter() {
+            centroid_bounds.grow_point(Self::tri_centroid(tri, pos, tri_ids));
+        }
+        let extent = centroid_bounds.max - centroid_bounds.min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+        indices.sort_by(|&a, &b| {
+            let ca = Self::tri_centroid(a, pos, tri_ids)[axis];
+            let cb = Self::tri_centroid(b, pos, tri_ids)[axis];
+            ca.partial_cmp(&cb).unwrap()
+        });
+
+        let mid = indices.len() / 2;
+        let (left_indices, right_indices) = indices.split_at_mut(mid);
+        let left = Self::build_bvh_range(nodes, left_indices, pos, tri_ids);
+        let right = Self::build_bvh_range(nodes, right_indices, pos, tri_ids);
+        nodes.push(BvhNode {
+            aabb: Aabb::empty(),
+            left,
+            right,
+            tri: BVH_NONE,
+        });
+        nodes.len() - 1
+    }
+
+    // topology (tri_ids) is static, so only the bounding boxes need updating
+    // each step; child nodes always precede their parent, so a single
+    // forward pass over `nodes` refits the whole tree bottom-up
+    fn refit_bvh(&self, bvh: &mut Bvh) {
+        for node in bvh.nodes.iter_mut() {
+            if node.tri != BVH_NONE {
+                let [a, b, c] = self.tri_ids[node.tri];
+                let mut aabb = Aabb::empty();
+                for &p in &[
+                    self.pos[a],
+                    self.pos[b],
+                    self.pos[c],
+                    self.prev[a],
+                    self.prev[b],
+                    self.prev[c],
+                ] {
+                    aabb.grow_point(p);
                 }
-                if rest_dist_sq < thickness_sq {
-                    min_dist = rest_dist_sq.sqrt();
+                node.aabb = aabb.expand(self.thickness);
+            }
+        }
+        for i in 0..bvh.nodes.len() {
+            if bvh.nodes[i].tri == BVH_NONE {
+                let (left, right) = (bvh.nodes[i].left, bvh.nodes[i].right);
+                bvh.nodes[i].aabb = Aabb::union(&bvh.nodes[left].aabb, &bvh.nodes[right].aabb);
+            }
+        }
+    }
+
+    fn query_bvh(bvh: &Bvh, node: usize, query: &Aabb, out: &mut Vec<usize>) {
+        let n = &bvh.nodes[node];
+        if !n.aabb.overlaps(query) {
+            return;
+        }
+        if n.tri != BVH_NONE {
+            out.push(n.tri);
+            return;
+        }
+        Self::query_bvh(bvh, n.left, query, out);
+        Self::query_bvh(bvh, n.right, query, out);
+    }
+
+    fn solve_vertex_triangle(&mut self, v: usize, tri: usize) {
+        let [ia, ib, ic] = self.tri_ids[tri];
+        if v == ia || v == ib || v == ic {
+            return;
+        }
+
+        let p0 = self.prev[v];
+        let dp = self.pos[v] - p0;
+        let a0 = self.prev[ia];
+        let da = self.pos[ia] - a0;
+        let b0 = self.prev[ib];
+        let db = self.pos[ib] - b0;
+        let c0 = self.prev[ic];
+        let dc = self.pos[ic] - c0;
+
+        let qa0 = a0 - p0;
+        let dqa = da - dp;
+        let qb0 = b0 - p0;
+        let dqb = db - dp;
+        let qc0 = c0 - p0;
+        let dqc = dc - dp;
+
+        let (c3, c2, c1, c0_coeff) = triple_product_cubic(qa0, dqa, qb0, dqb, qc0, dqc);
+
+        let mut hit_t: Option<f32> = None;
+        for t in solve_cubic_roots(c3, c2, c1, c0_coeff) {
+            if !(0.0..=1.0).contains(&t) {
+                continue;
+            }
+            let p = p0 + dp * t;
+            let a = a0 + da * t;
+            let b = b0 + db * t;
+            let c = c0 + dc * t;
+            if let Some((u, v, w)) = barycentric(p, a, b, c) {
+                const BARY_EPS: f32 = 1e-3;
+                if u >= -BARY_EPS && v >= -BARY_EPS && w >= -BARY_EPS {
+                    hit_t = Some(hit_t.map_or(t, |best| best.min(t)));
                 }
+            }
+        }
+        if hit_t.is_none() {
+            return;
+        }
 
-                // position correction
-                let dist = dist_sq.sqrt();
-                self.grad *= (min_dist - dist) / dist;
-                self.pos[id0] += self.grad * -0.5;
-                self.pos[id1] += self.grad * 0.5;
+        let Some((bu, bv, bw)) = barycentric(self.pos[v], self.pos[ia], self.pos[ib], self.pos[ic])
+        else {
+            return;
+        };
+        let contact = self.pos[ia] * bu + self.pos[ib] * bv + self.pos[ic] * bw;
+        let mut n = self.pos[v] - contact;
+        let dist = n.length();
+        if dist == 0.0 || dist >= self.thickness {
+            return;
+        }
+        n /= dist;
+        let c_val = dist - self.thickness;
 
-                // velocities
-                self.grad = self.pos[id0] - self.prev[id0];
-                self.grad1 = self.pos[id1] - self.prev[id1];
+        let w_v = self.inv_mass[v];
+        let weight = w_v
+            + bu * bu * self.inv_mass[ia]
+            + bv * bv * self.inv_mass[ib]
+            + bw * bw * self.inv_mass[ic];
+        if weight == 0.0 {
+            return;
+        }
+        let lambda = -c_val / weight;
+        self.pos[v] += n * lambda * w_v;
+        self.pos[ia] -= n * lambda * bu * self.inv_mass[ia];
+        self.pos[ib] -= n * lambda * bv * self.inv_mass[ib];
+        self.pos[ic] -= n * lambda * bw * self.inv_mass[ic];
+    }
 
-                // average velocity
-                self.grad2 = (self.grad + self.grad1) * 0.5;
+    fn solve_edge_edge(&mut self, edge_a: (usize, usize), edge_b: (usize, usize)) {
+        let (ia0, ia1) = edge_a;
+        let (ib0, ib1) = edge_b;
 
-                // velocity correction
-                self.grad = self.grad2 - self.grad;
-                self.grad1 = self.grad2 - self.grad1;
+        let pa0 = self.prev[ia0];
+        let dpa = self.pos[ia0] - pa0;
+        let pa1_0 = self.prev[ia1];
+        let dpa1 = self.pos[ia1] - pa1_0;
+        let qa0 = self.prev[ib0];
+        let dqa = self.pos[ib0] - qa0;
+        let qb0 = self.prev[ib1];
+        let dqb = self.pos[ib1] - qb0;
 
-                // add corrections
-                let friction = 0.0;
-                self.pos[id0] += self.grad * friction;
-                self.pos[id1] += self.grad1 * friction;
+        let r0 = qa0 - pa0;
+        let dr = dqa - dpa;
+        let u0 = pa1_0 - pa0;
+        let du = dpa1 - dpa;
+        let v0 = qb0 - qa0;
+        let dv = dqb - dqa;
+
+        let (c3, c2, c1, c0_coeff) = triple_product_cubic(r0, dr, u0, du, v0, dv);
+
+        let mut hit = false;
+        for t in solve_cubic_roots(c3, c2, c1, c0_coeff) {
+            if (0.0..=1.0).contains(&t) {
+                hit = true;
+                break;
             }
         }
+        if !hit {
+            return;
+        }
+
+        let (s, t) = closest_segment_params(
+            self.pos[ia0],
+            self.pos[ia1],
+            self.pos[ib0],
+            self.pos[ib1],
+        );
+        let cp_a = self.pos[ia0].lerp(self.pos[ia1], s);
+        let cp_b = self.pos[ib0].lerp(self.pos[ib1], t);
+        let diff = cp_b - cp_a;
+        let dist = diff.length();
+        if dist == 0.0 || dist >= self.thickness {
+            return;
+        }
+        let n = diff / dist;
+        let c_val = dist - self.thickness;
+
+        let wa0 = self.inv_mass[ia0] * (1.0 - s) * (1.0 - s);
+        let wa1 = self.inv_mass[ia1] * s * s;
+        let wb0 = self.inv_mass[ib0] * (1.0 - t) * (1.0 - t);
+        let wb1 = self.inv_mass[ib1] * t * t;
+        let weight = wa0 + wa1 + wb0 + wb1;
+        if weight == 0.0 {
+            return;
+        }
+        let lambda = -c_val / weight;
+        self.pos[ia0] -= n * lambda * (1.0 - s) * self.inv_mass[ia0];
+        self.pos[ia1] -= n * lambda * s * self.inv_mass[ia1];
+        self.pos[ib0] += n * lambda * (1.0 - t) * self.inv_mass[ib0];
+        self.pos[ib1] += n * lambda * t * self.inv_mass[ib1];
+    }
+
+    /// Smooth per-particle normals, accumulated as area-weighted face
+    /// normals over `tri_ids`. Recompute every frame, since the cloth
+    /// deforms under simulation.
+    pub fn surface_normals(&self) -> Vec<Vec3> {
+        tangent_space::accumulate_normals(self.num_particles, self.tri_ids.iter().copied(), &self.pos)
+    }
+
+    /// Per-particle tangents over `tri_ids`; see [`tangent_space`] for the
+    /// construction. `uvs` is indexed by particle id, matching `self.pos`.
+    /// Tangents only need recomputing when the UVs or rest topology
+    /// change, unlike `surface_normals`.
+    pub fn surface_tangents(&self, uvs: &[Vec2]) -> Vec<Vec4> {
+        let normals = self.surface_normals();
+        tangent_space::accumulate_tangents(
+            self.num_particles,
+            self.tri_ids.iter().copied(),
+            &self.pos,
+            uvs,
+            &normals,
+        )
     }
 
     pub fn start_grab(&mut self, pos: &Vec3) {
@@ -360,4 +987,136 @@ impl Cloth {
         }
         self.grab_id = None;
     }
+}
+
+// coefficients (c3, c2, c1, c0) of f(t) = r(t) . (u(t) x v(t)), where r, u, v
+// are each linearly interpolated from t=0 to t=1 (e.g. r(t) = r0 + t*dr).
+// Used by both the vertex-triangle and edge-edge CCD tests: a root in [0,1]
+// is a time at which the four interpolated points become coplanar.
+fn triple_product_cubic(r0: Vec3, dr: Vec3, u0: Vec3, du: Vec3, v0: Vec3, dv: Vec3) -> (f32, f32, f32, f32) {
+    let w0 = u0.cross(v0);
+    let w1 = u0.cross(dv) + du.cross(v0);
+    let w2 = du.cross(dv);
+
+    let c0 = r0.dot(w0);
+    let c1 = r0.dot(w1) + dr.dot(w0);
+    let c2 = r0.dot(w2) + dr.dot(w1);
+    let c3 = dr.dot(w2);
+    (c3, c2, c1, c0)
+}
+
+// real roots of a*t^3 + b*t^2 + c*t + d = 0, via the depressed-cubic /
+// trigonometric method; falls back to the quadratic/linear case when a ~= 0
+fn solve_cubic_roots(a: f32, b: f32, c: f32, d: f32) -> Vec<f32> {
+    const EPS: f32 = 1e-9;
+    if a.abs() < EPS {
+        return solve_quadratic_roots(b, c, d);
+    }
+
+    let pb = b / a;
+    let pc = c / a;
+    let pd = d / a;
+    let offset = pb / 3.0;
+    let p = pc - pb * pb / 3.0;
+    let q = 2.0 * pb * pb * pb / 27.0 - pb * pc / 3.0 + pd;
+    let discriminant = q * q / 4.0 + p * p * p / 27.0;
+
+    let mut roots = Vec::with_capacity(3);
+    if discriminant > EPS {
+        let sqrt_disc = discriminant.sqrt();
+        let u = (-q / 2.0 + sqrt_disc).cbrt();
+        let v = (-q / 2.0 - sqrt_disc).cbrt();
+        roots.push(u + v - offset);
+    } else if discriminant.abs() <= EPS {
+        let u = (-q / 2.0).cbrt();
+        roots.push(2.0 * u - offset);
+        roots.push(-u - offset);
+    } else {
+        let r = (-p * p * p / 27.0).sqrt();
+        let phi = (-q / (2.0 * r)).clamp(-1.0, 1.0).acos();
+        let m = 2.0 * r.cbrt();
+        for k in 0..3 {
+            let angle = (phi + 2.0 * std::f32::consts::PI * k as f32) / 3.0;
+            roots.push(m * angle.cos() - offset);
+        }
+    }
+    roots
+}
+
+fn solve_quadratic_roots(a: f32, b: f32, c: f32) -> Vec<f32> {
+    const EPS: f32 = 1e-9;
+    if a.abs() < EPS {
+        if b.abs() < EPS {
+            return vec![];
+        }
+        return vec![-c / b];
+    }
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return vec![];
+    }
+    let sq = discriminant.sqrt();
+    vec![(-b + sq) / (2.0 * a), (-b - sq) / (2.0 * a)]
+}
+
+// barycentric coordinates (u, v, w) of p projected onto the plane of
+// triangle (a, b, c), such that p ~= u*a + v*b + w*c; None if the triangle is
+// degenerate
+fn barycentric(p: Vec3, a: Vec3, b: Vec3, c: Vec3) -> Option<(f32, f32, f32)> {
+    let v0 = b - a;
+    let v1 = c - a;
+    let v2 = p - a;
+    let d00 = v0.dot(v0);
+    let d01 = v0.dot(v1);
+    let d11 = v1.dot(v1);
+    let d20 = v2.dot(v0);
+    let d21 = v2.dot(v1);
+    let denom = d00 * d11 - d01 * d01;
+    if denom.abs() < 1e-12 {
+        return None;
+    }
+    let v = (d11 * d20 - d01 * d21) / denom;
+    let w = (d00 * d21 - d01 * d20) / denom;
+    let u = 1.0 - v - w;
+    Some((u, v, w))
+}
+
+// parameters (s, t) in [0,1] of the closest points on segments p0->p1 and
+// q0->q1, i.e. the points p0+(p1-p0)*s and q0+(q1-q0)*t
+fn closest_segment_params(p0: Vec3, p1: Vec3, q0: Vec3, q1: Vec3) -> (f32, f32) {
+    const EPS: f32 = 1e-8;
+    let d1 = p1 - p0;
+    let d2 = q1 - q0;
+    let r = p0 - q0;
+    let a = d1.dot(d1);
+    let e = d2.dot(d2);
+    let f = d2.dot(r);
+
+    if a <= EPS && e <= EPS {
+        return (0.0, 0.0);
+    }
+    if a <= EPS {
+        return (0.0, (f / e).clamp(0.0, 1.0));
+    }
+    let c = d1.dot(r);
+    if e <= EPS {
+        return ((-c / a).clamp(0.0, 1.0), 0.0);
+    }
+
+    let b = d1.dot(d2);
+    let denom = a * e - b * b;
+    let mut s = if denom.abs() > EPS {
+        ((b * f - c * e) / denom).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    let mut t = (b * s + f) / e;
+    if t < 0.0 {
+        t = 0.0;
+        s = (-c / a).clamp(0.0, 1.0);
+    } else if t > 1.0 {
+        t = 1.0;
+        s = ((b - c) / a).clamp(0.0, 1.0);
+    }
+    (s, t)
 }
\ No newline at end of file
@@ -1,4 +1,5 @@
-use glam::{vec3, Vec3};
+use crate::tangent_space;
+use glam::{vec3, Vec2, Vec3, Vec4};
 
 use crate::mesh::{self, TetMeshData};
 
@@ -29,8 +30,22 @@ pub struct SoftBody {
     pub edge_compliance: f32,
     pub vol_compliance: f32,
 
+    pub static_friction: f32,
+    pub dynamic_friction: f32,
+    pub restitution: f32,
+
     // stored for reset
     mesh: TetMeshData,
+
+    pub visual_tri_ids: Vec<[usize; 3]>,
+    visual_bindings: Vec<VisualBinding>,
+}
+
+// a visual (render) vertex's binding to the coarse tet mesh: which tet
+// contains it, and its barycentric weights within that tet
+struct VisualBinding {
+    tet_id: usize,
+    weights: [f32; 4],
 }
 
 impl SoftBody {
@@ -63,12 +78,147 @@ impl SoftBody {
             edge_compliance: edge_compliance,
             vol_compliance: vol_compliance,
 
+            static_friction: 0.0,
+            dynamic_friction: 0.0,
+            restitution: 0.0,
+
             mesh,
+
+            visual_tri_ids: vec![],
+            visual_bindings: vec![],
         };
         body.init();
         body
     }
 
+    /// Embeds a high-resolution render mesh into the coarse tet mesh: each
+    /// visual vertex is located inside its containing tetrahedron (or, if it
+    /// falls outside every tet, bound to the nearest tet's closest surface
+    /// point) and stored as a set of barycentric weights. Call once after
+    /// construction; `visual_positions`/`visual_normals` reconstruct the
+    /// skin every frame from the current `pos`.
+    pub fn bind_visual_mesh(&mut self, vertices: Vec<Vec3>, tri_ids: Vec<[usize; 3]>) {
+        self.visual_bindings = vertices.iter().map(|&p| self.bind_visual_vertex(p)).collect();
+        self.visual_tri_ids = tri_ids;
+    }
+
+    fn bind_visual_vertex(&self, p: Vec3) -> VisualBinding {
+        const INSIDE_EPS: f32 = 1e-4;
+        for tet_id in 0..self.num_tets {
+            let weights = self.tet_barycentric(tet_id, p);
+            if weights.iter().all(|&w| w >= -INSIDE_EPS) {
+                return VisualBinding { tet_id, weights };
+            }
+        }
+
+        // outside every tet: bind to whichever tet's closest surface point
+        // (barycentric weights clamped back into the simplex) is nearest
+        let mut best_tet = 0;
+        let mut best_weights = [0.25; 4];
+        let mut best_dist_sq = f32::MAX;
+        for tet_id in 0..self.num_tets {
+            let weights = clamp_to_simplex(self.tet_barycentric(tet_id, p));
+            let tet = self.tet_ids[tet_id];
+            let closest = self.pos[tet[0]] * weights[0]
+                + self.pos[tet[1]] * weights[1]
+                + self.pos[tet[2]] * weights[2]
+                + self.pos[tet[3]] * weights[3];
+            let dist_sq = (closest - p).length_squared();
+            if dist_sq < best_dist_sq {
+                best_dist_sq = dist_sq;
+                best_tet = tet_id;
+                best_weights = weights;
+            }
+        }
+        VisualBinding {
+            tet_id: best_tet,
+            weights: best_weights,
+        }
+    }
+
+    fn tet_barycentric(&self, tet_id: usize, p: Vec3) -> [f32; 4] {
+        let tet = self.tet_ids[tet_id];
+        let p0 = self.pos[tet[0]];
+        let p1 = self.pos[tet[1]];
+        let p2 = self.pos[tet[2]];
+        let p3 = self.pos[tet[3]];
+        let vol = signed_tet_volume(p0, p1, p2, p3);
+        if vol.abs() < 1e-12 {
+            return [0.0; 4];
+        }
+        [
+            signed_tet_volume(p, p1, p2, p3) / vol,
+            signed_tet_volume(p0, p, p2, p3) / vol,
+            signed_tet_volume(p0, p1, p, p3) / vol,
+            signed_tet_volume(p0, p1, p2, p) / vol,
+        ]
+    }
+
+    /// Reconstructs every visual vertex as a gather-multiply-add of its
+    /// bound tet's four current positions.
+    pub fn visual_positions(&self) -> Vec<Vec3> {
+        self.visual_bindings
+            .iter()
+            .map(|b| {
+                let tet = self.tet_ids[b.tet_id];
+                self.pos[tet[0]] * b.weights[0]
+                    + self.pos[tet[1]] * b.weights[1]
+                    + self.pos[tet[2]] * b.weights[2]
+                    + self.pos[tet[3]] * b.weights[3]
+            })
+            .collect()
+    }
+
+    /// Interpolated per-visual-vertex normals, skinned the same way as
+    /// `visual_positions` from area-weighted surface normals at the
+    /// particles of the bound tet.
+    pub fn visual_normals(&self) -> Vec<Vec3> {
+        let surface_normals = self.surface_normals();
+        self.visual_bindings
+            .iter()
+            .map(|b| {
+                let tet = self.tet_ids[b.tet_id];
+                let n = surface_normals[tet[0]] * b.weights[0]
+                    + surface_normals[tet[1]] * b.weights[1]
+                    + surface_normals[tet[2]] * b.weights[2]
+                    + surface_normals[tet[3]] * b.weights[3];
+                if n == Vec3::ZERO {
+                    n
+                } else {
+                    n.normalize()
+                }
+            })
+            .collect()
+    }
+
+    /// Smooth per-particle normals, accumulated as area-weighted face
+    /// normals over the surface triangles. Recompute every frame, since
+    /// the tet mesh deforms under simulation.
+    pub fn surface_normals(&self) -> Vec<Vec3> {
+        let surface_tri_ids = self.surface_tri_ids();
+        tangent_space::accumulate_normals(
+            self.num_particles,
+            surface_tri_ids.chunks_exact(3).map(|t| [t[0], t[1], t[2]]),
+            &self.pos,
+        )
+    }
+
+    /// Per-particle tangents over the surface triangles; see
+    /// [`tangent_space`] for the construction. `uvs` is indexed by particle
+    /// id, matching `self.pos`. Tangents only need recomputing when the
+    /// UVs or rest topology change, unlike `surface_normals`.
+    pub fn surface_tangents(&self, uvs: &[Vec2]) -> Vec<Vec4> {
+        let normals = self.surface_normals();
+        let surface_tri_ids = self.surface_tri_ids();
+        tangent_space::accumulate_tangents(
+            self.num_particles,
+            surface_tri_ids.chunks_exact(3).map(|t| [t[0], t[1], t[2]]),
+            &self.pos,
+            uvs,
+            &normals,
+        )
+    }
+
     pub fn surface_tri_ids(&self) -> Vec<usize> {
         self.mesh.tet_surface_tri_ids.clone()
     }
@@ -106,8 +256,31 @@ impl SoftBody {
             self.prev[i] = self.pos[i];
             self.pos[i] += self.vel[i] * self.dt;
             if self.pos[i].y < 0.0 {
-                self.pos[i] = self.prev[i];
+                let v_n_in = self.vel[i].y;
+                let d_n = -self.pos[i].y;
+                let tangent = vec3(self.pos[i].x - self.prev[i].x, 0.0, self.pos[i].z - self.prev[i].z);
                 self.pos[i].y = 0.0;
+
+                // Coulomb friction: stick if the tangential slip is within
+                // the static cone, otherwise clamp it to the dynamic limit
+                let t_len = tangent.length();
+                if t_len > 0.0 {
+                    if t_len < self.static_friction * d_n {
+                        self.pos[i].x = self.prev[i].x;
+                        self.pos[i].z = self.prev[i].z;
+                    } else {
+                        let removed = (self.dynamic_friction * d_n).min(t_len);
+                        let scale = 1.0 - removed / t_len;
+                        self.pos[i].x = self.prev[i].x + tangent.x * scale;
+                        self.pos[i].z = self.prev[i].z + tangent.z * scale;
+                    }
+                }
+
+                // restitution: fudge `prev.y` so the post_solve vel
+                // recompute reflects the incoming normal velocity scaled by
+                // `restitution`
+                let target_vel_y = if v_n_in < 0.0 { -self.restitution * v_n_in } else { 0.0 };
+                self.prev[i].y = self.pos[i].y - target_vel_y * self.dt;
             }
         }
     }
@@ -249,3 +422,22 @@ impl SoftBody {
         self.grab_id = None;
     }
 }
+
+fn signed_tet_volume(a: Vec3, b: Vec3, c: Vec3, d: Vec3) -> f32 {
+    (b - a).cross(c - a).dot(d - a) / 6.0
+}
+
+// clamps barycentric weights back into the simplex (each >= 0, summing to 1)
+// by zeroing negative weights and renormalizing the rest
+fn clamp_to_simplex(weights: [f32; 4]) -> [f32; 4] {
+    let mut w = weights.map(|x| x.max(0.0));
+    let sum: f32 = w.iter().sum();
+    if sum > 1e-8 {
+        for x in w.iter_mut() {
+            *x /= sum;
+        }
+    } else {
+        w = [0.25; 4];
+    }
+    w
+}
@@ -0,0 +1,77 @@
+use glam::{vec4, Vec2, Vec3, Vec4};
+
+// shared by Cloth (self_collision_15) and SoftBody (softbodies_10): both
+// expose a deforming triangulated surface and need the same area-weighted
+// normal and mikktspace tangent construction over it
+
+/// Area-weighted per-vertex normals, accumulated from face normals over
+/// `tri_ids` and renormalized.
+pub(crate) fn accumulate_normals(
+    num_vertices: usize,
+    tri_ids: impl Iterator<Item = [usize; 3]>,
+    pos: &[Vec3],
+) -> Vec<Vec3> {
+    let mut normals = vec![Vec3::ZERO; num_vertices];
+    for [ia, ib, ic] in tri_ids {
+        let face_normal = (pos[ib] - pos[ia]).cross(pos[ic] - pos[ia]);
+        normals[ia] += face_normal;
+        normals[ib] += face_normal;
+        normals[ic] += face_normal;
+    }
+    for n in normals.iter_mut() {
+        if *n != Vec3::ZERO {
+            *n = n.normalize();
+        }
+    }
+    normals
+}
+
+/// Per-vertex tangents built with the mikktspace construction: each
+/// triangle's edge vectors and UV deltas give a per-face tangent/bitangent,
+/// accumulated per vertex, then Gram-Schmidt orthonormalized against
+/// `normals`. The handedness of the original (non-orthonormalized) basis is
+/// carried in the `w` component, as renderers expect. `uvs` and `normals`
+/// are indexed the same way as `pos`.
+pub(crate) fn accumulate_tangents(
+    num_vertices: usize,
+    tri_ids: impl Iterator<Item = [usize; 3]>,
+    pos: &[Vec3],
+    uvs: &[Vec2],
+    normals: &[Vec3],
+) -> Vec<Vec4> {
+    let mut tangents = vec![Vec3::ZERO; num_vertices];
+    let mut bitangents = vec![Vec3::ZERO; num_vertices];
+    for [ia, ib, ic] in tri_ids {
+        let e1 = pos[ib] - pos[ia];
+        let e2 = pos[ic] - pos[ia];
+        let duv1 = uvs[ib] - uvs[ia];
+        let duv2 = uvs[ic] - uvs[ia];
+        let denom = duv1.x * duv2.y - duv2.x * duv1.y;
+        if denom.abs() < 1e-12 {
+            continue;
+        }
+        let r = 1.0 / denom;
+        let tangent = (e1 * duv2.y - e2 * duv1.y) * r;
+        let bitangent = (e2 * duv1.x - e1 * duv2.x) * r;
+        for id in [ia, ib, ic] {
+            tangents[id] += tangent;
+            bitangents[id] += bitangent;
+        }
+    }
+    (0..num_vertices)
+        .map(|i| {
+            let n = normals[i];
+            let t = tangents[i] - n * n.dot(tangents[i]);
+            if t.length_squared() < 1e-12 {
+                return vec4(0.0, 0.0, 0.0, 1.0);
+            }
+            let t = t.normalize();
+            let w = if n.cross(t).dot(bitangents[i]) < 0.0 {
+                -1.0
+            } else {
+                1.0
+            };
+            vec4(t.x, t.y, t.z, w)
+        })
+        .collect()
+}